@@ -0,0 +1,132 @@
+use crate::Provider;
+use alloy_eips::BlockNumberOrTag;
+use alloy_network::Network;
+use alloy_transport::TransportResult;
+
+/// Minimum `max_priority_fee_per_gas` (wei) used when the network reports all-zero priority fees,
+/// e.g. on a quiet chain where every block in the sampled window was empty.
+const DEFAULT_MIN_PRIORITY_FEE: u128 = 1_000_000_000; // 1 gwei
+
+/// Default number of most-recent blocks sampled by [`estimate_eip1559_fees`].
+const DEFAULT_FEE_HISTORY_BLOCK_COUNT: u64 = 10;
+
+/// Default reward percentile requested from `eth_feeHistory`.
+const DEFAULT_REWARD_PERCENTILE: f64 = 50.0;
+
+/// Default multiplier applied to the latest `baseFeePerGas` to absorb base-fee growth between
+/// estimation and inclusion.
+const DEFAULT_BASE_FEE_HEADROOM_MULTIPLIER: f64 = 2.0;
+
+/// A suggested `max_fee_per_gas`/`max_priority_fee_per_gas` pair for an EIP-1559 transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeEstimate {
+    /// The suggested `max_fee_per_gas`, in wei.
+    pub max_fee_per_gas: u128,
+    /// The suggested `max_priority_fee_per_gas`, in wei.
+    pub max_priority_fee_per_gas: u128,
+}
+
+/// Configuration for [`estimate_eip1559_fees`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeEstimatorConfig {
+    /// Number of most-recent blocks to sample via `eth_feeHistory`.
+    pub block_count: u64,
+    /// Reward percentile requested from `eth_feeHistory`, used to derive the priority fee.
+    pub reward_percentile: f64,
+    /// Multiplier applied to the latest `baseFeePerGas` before adding the priority fee, to
+    /// absorb base-fee growth between estimation and the transaction's inclusion.
+    pub base_fee_headroom_multiplier: f64,
+    /// Floor applied to the averaged priority fee, used when the network reports zeros.
+    pub min_priority_fee: u128,
+}
+
+impl Default for FeeEstimatorConfig {
+    fn default() -> Self {
+        Self {
+            block_count: DEFAULT_FEE_HISTORY_BLOCK_COUNT,
+            reward_percentile: DEFAULT_REWARD_PERCENTILE,
+            base_fee_headroom_multiplier: DEFAULT_BASE_FEE_HEADROOM_MULTIPLIER,
+            min_priority_fee: DEFAULT_MIN_PRIORITY_FEE,
+        }
+    }
+}
+
+/// The average `reward_percentile` priority fee across `reward`'s per-block entries, floored at
+/// `min_priority_fee`.
+///
+/// `block_rewards.first()` is `None` only for an empty block's empty `reward` array (skipped); a
+/// block that paid a real zero tip at the requested percentile still yields `Some(0)` and is
+/// counted, so the network's actual zeros are averaged in and only the final result is floored.
+pub(crate) fn average_priority_fee(reward: &[Vec<u128>], min_priority_fee: u128) -> u128 {
+    let priority_fees: Vec<u128> =
+        reward.iter().filter_map(|block_rewards| block_rewards.first().copied()).collect();
+
+    if priority_fees.is_empty() {
+        min_priority_fee
+    } else {
+        let average = priority_fees.iter().sum::<u128>() / priority_fees.len() as u128;
+        average.max(min_priority_fee)
+    }
+}
+
+/// Estimate `max_fee_per_gas`/`max_priority_fee_per_gas` for an EIP-1559 transaction using
+/// `eth_feeHistory`, tuned for the cycles-limited ICP context instead of hardcoding gas prices.
+///
+/// The priority fee is the average of `config.reward_percentile` across the sampled window (see
+/// [`average_priority_fee`]), floored at `config.min_priority_fee`. The max fee is
+/// `latest_base_fee * config.base_fee_headroom_multiplier + priority_fee`.
+pub async fn estimate_eip1559_fees<P, N>(
+    provider: &P,
+    config: FeeEstimatorConfig,
+) -> TransportResult<FeeEstimate>
+where
+    P: Provider<N>,
+    N: Network,
+{
+    let fee_history = provider
+        .get_fee_history(config.block_count, BlockNumberOrTag::Latest, &[config.reward_percentile])
+        .await?;
+
+    let max_priority_fee_per_gas =
+        average_priority_fee(&fee_history.reward.unwrap_or_default(), config.min_priority_fee);
+
+    let base_fee_per_gas = fee_history.base_fee_per_gas.last().copied().unwrap_or_default();
+    let max_fee_per_gas = (base_fee_per_gas as f64 * config.base_fee_headroom_multiplier) as u128
+        + max_priority_fee_per_gas;
+
+    Ok(FeeEstimate { max_fee_per_gas, max_priority_fee_per_gas })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_blocks_are_skipped_not_floored() {
+        // Only one block actually reports a reward; the two empty ones must not drag the
+        // average toward the floor or be mistaken for real zero-tip blocks.
+        let rewards = vec![vec![], vec![100], vec![]];
+        assert_eq!(average_priority_fee(&rewards, 10), 100);
+    }
+
+    #[test]
+    fn genuine_zero_fee_blocks_are_averaged_in() {
+        // A quiet chain where every block really did pay ~0 tip: the true zeros must be
+        // averaged in (giving 0 here), with the floor applied only to the final result.
+        let rewards = vec![vec![0], vec![0], vec![0]];
+        assert_eq!(average_priority_fee(&rewards, 10), 10);
+    }
+
+    #[test]
+    fn mixed_real_zero_and_nonzero_blocks_average_correctly() {
+        let rewards = vec![vec![0], vec![100], vec![]];
+        // (0 + 100) / 2 = 50, well above the floor.
+        assert_eq!(average_priority_fee(&rewards, 10), 50);
+    }
+
+    #[test]
+    fn no_reward_data_falls_back_to_floor() {
+        let rewards: Vec<Vec<u128>> = vec![vec![], vec![]];
+        assert_eq!(average_priority_fee(&rewards, 10), 10);
+    }
+}
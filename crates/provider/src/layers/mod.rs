@@ -0,0 +1,4 @@
+#[cfg(any(test, feature = "icp"))]
+mod verify;
+#[cfg(any(test, feature = "icp"))]
+pub use verify::{VerifyingProvider, VerifyingProviderError};
@@ -0,0 +1,280 @@
+use crate::{IcpProvider, Provider};
+use alloy_eips::BlockId;
+use alloy_network::Ethereum;
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use alloy_rlp::Encodable;
+use alloy_transport::TransportError;
+use alloy_trie::{proof::verify_proof, Nibbles, TrieAccount};
+
+/// Default `max_response_size` (bytes) used for the `eth_getProof` sub-call issued by
+/// [`VerifyingProvider`]. `eth_getProof` responses carry a full Merkle-Patricia proof and are
+/// much larger than the `eth_getBalance`/`eth_getStorageAt`/`eth_getCode` responses they verify,
+/// so the default chosen for those methods in
+/// [`IcpTransport::estimate_max_response_size`](alloy_transport_icp::IcpTransport) is not enough.
+const DEFAULT_PROOF_MAX_RESPONSE_SIZE: u64 = 50_000;
+
+/// Error returned by [`VerifyingProvider`] when a result cannot be verified against the account
+/// or storage proof obtained via `eth_getProof`.
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyingProviderError {
+    /// The underlying RPC call failed.
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    /// The account proof does not verify against the block's state root.
+    #[error("account proof for {address} did not verify against state root {state_root}")]
+    InvalidAccountProof {
+        /// The account the proof was for.
+        address: Address,
+        /// The block's state root the proof was checked against.
+        state_root: B256,
+    },
+    /// A storage proof does not verify against the account's storage root.
+    #[error("storage proof for {address} slot {key} did not verify against storage root {storage_root}")]
+    InvalidStorageProof {
+        /// The account the storage slot belongs to.
+        address: Address,
+        /// The storage slot.
+        key: B256,
+        /// The account's storage root the proof was checked against.
+        storage_root: B256,
+    },
+    /// The returned code does not hash to the account's proven `codeHash`.
+    #[error("code for {address} does not match proven code hash {code_hash}")]
+    InvalidCodeHash {
+        /// The account the code belongs to.
+        address: Address,
+        /// The account's proven code hash.
+        code_hash: B256,
+    },
+    /// `eth_getBlockByNumber`/`eth_getBlockByHash` returned no block for the requested `block`.
+    #[error("no block found for {block:?}")]
+    MissingBlock {
+        /// The block that was requested.
+        block: BlockId,
+    },
+    /// The `eth_getProof` response did not include a `storageProof` entry for a requested slot.
+    /// A misbehaving or malicious provider can trigger this by simply omitting the entry, so it
+    /// must be surfaced as an ordinary error rather than trusted to always be present.
+    #[error("eth_getProof response for {address} is missing a storage proof for slot {key}")]
+    MissingStorageProof {
+        /// The account the storage slot belongs to.
+        address: Address,
+        /// The storage slot that was requested but not returned.
+        key: B256,
+    },
+    /// `inner` is configured with fewer than 2 [`RpcService`](alloy_transport_icp::RpcService)s,
+    /// so the `stateRoot` this layer checks proofs against would be taken on the word of the
+    /// same single provider that also supplies the proof, making the verification circular.
+    #[error(
+        "VerifyingProvider requires inner to be configured with at least 2 RpcServices (see \
+         IcpConfig::with_services), but only {configured} were found; otherwise the eth_getProof \
+         proof and the stateRoot it is checked against both come from the same untrusted provider"
+    )]
+    InsufficientProviders {
+        /// The number of [`RpcService`](alloy_transport_icp::RpcService)s `inner` was actually
+        /// configured with.
+        configured: usize,
+    },
+}
+
+/// An opt-in provider layer that verifies `eth_getBalance`, `eth_getStorageAt`, and `eth_getCode`
+/// results against a Merkle-Patricia proof obtained via `eth_getProof` for the same block, so
+/// that a canister does not have to trust any single RPC provider's word for account state.
+///
+/// This mirrors the verification a light client performs against a trusted block header: the
+/// layer fetches the requested value from the (untrusted) RPC provider, fetches an `eth_getProof`
+/// for the same account/block, and walks the trie from the block's `stateRoot` to confirm the
+/// value is the one the proof commits to.
+///
+/// The block's `stateRoot` is itself fetched through `inner`, so [`Self::new`] requires `inner`
+/// to be configured with [`IcpConfig::with_services`](alloy_transport_icp::IcpConfig::with_services)
+/// over at least 2 independent providers: the `stateRoot` is then only accepted once those
+/// providers agree (see [`ConsensusStrategy`](alloy_transport_icp::ConsensusStrategy)), rather
+/// than taken on the word of whichever single provider also produced the proof being checked
+/// against it. A single-service `inner` would make the proof and the root it is checked against
+/// both come from that one provider, letting a malicious provider trivially forge a
+/// self-consistent pair and defeat the verification entirely — so [`Self::new`] panics rather
+/// than constructing a [`VerifyingProvider`] with that property.
+#[derive(Clone, Debug)]
+pub struct VerifyingProvider<N = Ethereum> {
+    inner: IcpProvider<N>,
+    proof_max_response_size: u64,
+}
+
+impl<N: alloy_network::Network> VerifyingProvider<N> {
+    /// Wrap `inner`, verifying reads against proofs fetched with the default
+    /// `max_response_size` ([`DEFAULT_PROOF_MAX_RESPONSE_SIZE`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `inner` is configured with fewer than 2
+    /// [`RpcService`](alloy_transport_icp::RpcService)s (see
+    /// [`IcpConfig::with_services`](alloy_transport_icp::IcpConfig::with_services)): with only
+    /// one provider configured, the `stateRoot` this layer checks proofs against would come from
+    /// the same untrusted provider as the proof itself, making the "verification" circular and
+    /// defeating the entire point of this layer.
+    pub fn new(inner: IcpProvider<N>) -> Self {
+        let configured = inner.client().transport().services().len();
+        assert!(
+            configured >= 2,
+            "{}",
+            VerifyingProviderError::InsufficientProviders { configured }
+        );
+        Self { inner, proof_max_response_size: DEFAULT_PROOF_MAX_RESPONSE_SIZE }
+    }
+
+    /// Set the `max_response_size` used for the `eth_getProof` sub-call.
+    pub const fn set_proof_max_response_size(mut self, proof_max_response_size: u64) -> Self {
+        self.proof_max_response_size = proof_max_response_size;
+        self
+    }
+
+    /// Fetch the `eth_getProof` for `address`/`keys` at `block` via a one-off transport with
+    /// `max_response_size` raised to [`Self::proof_max_response_size`], since proofs are much
+    /// larger than the reads they verify.
+    async fn get_proof(
+        &self,
+        address: Address,
+        keys: Vec<B256>,
+        block: BlockId,
+    ) -> Result<alloy_rpc_types_eth::EIP1186AccountProofResponse, VerifyingProviderError> {
+        let mut transport = self.inner.client().transport().clone();
+        transport.set_max_response_size(self.proof_max_response_size);
+        let proof_provider = IcpProvider::<N>::new(transport);
+
+        Ok(proof_provider.get_proof(address, keys).block_id(block).await?)
+    }
+
+    /// Fetch `block`'s `stateRoot` through `inner`. `inner` is required (see [`Self::new`]) to be
+    /// configured with several providers (see
+    /// [`IcpConfig::with_services`](alloy_transport_icp::IcpConfig::with_services)), so this root
+    /// is only accepted once they agree, rather than taken on the word of whichever single
+    /// provider also serves the proof being checked against it. Re-checked here, not just in
+    /// [`Self::new`], in case `inner`'s transport was reconfigured down to one service after
+    /// construction.
+    async fn get_state_root(&self, block: BlockId) -> Result<B256, VerifyingProviderError> {
+        let configured = self.inner.client().transport().services().len();
+        if configured < 2 {
+            return Err(VerifyingProviderError::InsufficientProviders { configured });
+        }
+
+        let header = self
+            .inner
+            .get_block(block)
+            .await?
+            .ok_or(VerifyingProviderError::MissingBlock { block })?;
+        Ok(header.header.state_root)
+    }
+
+    /// Verify `proof.account_proof` against `state_root`, returning the proven [`TrieAccount`].
+    fn verify_account(
+        proof: &alloy_rpc_types_eth::EIP1186AccountProofResponse,
+        state_root: B256,
+    ) -> Result<TrieAccount, VerifyingProviderError> {
+        let account = TrieAccount {
+            nonce: proof.nonce,
+            balance: proof.balance,
+            storage_root: proof.storage_hash,
+            code_hash: proof.code_hash,
+        };
+        let mut encoded = Vec::new();
+        account.encode(&mut encoded);
+
+        let key = Nibbles::unpack(keccak256(proof.address));
+        verify_proof(state_root, key, Some(encoded), &proof.account_proof).map_err(|_| {
+            VerifyingProviderError::InvalidAccountProof { address: proof.address, state_root }
+        })?;
+
+        Ok(account)
+    }
+
+    /// Get the balance of `address` at `block`, verified against an `eth_getProof` for the same
+    /// account and block and against `block`'s `stateRoot` (see [`Self::get_state_root`]).
+    pub async fn get_verified_balance(
+        &self,
+        address: Address,
+        block: BlockId,
+    ) -> Result<U256, VerifyingProviderError> {
+        let state_root = self.get_state_root(block).await?;
+        let proof = self.get_proof(address, Vec::new(), block).await?;
+        let account = Self::verify_account(&proof, state_root)?;
+
+        let balance = self.inner.get_balance(address).block_id(block).await?;
+        if balance != account.balance {
+            return Err(VerifyingProviderError::InvalidAccountProof { address, state_root });
+        }
+
+        Ok(balance)
+    }
+
+    /// Get the value of storage slot `key` of `address` at `block`, verified against an
+    /// `eth_getProof` for the same account, slot, and block and against `block`'s `stateRoot`
+    /// (see [`Self::get_state_root`]).
+    pub async fn get_verified_storage_at(
+        &self,
+        address: Address,
+        key: B256,
+        block: BlockId,
+    ) -> Result<U256, VerifyingProviderError> {
+        let state_root = self.get_state_root(block).await?;
+        let proof = self.get_proof(address, vec![key], block).await?;
+        let account = Self::verify_account(&proof, state_root)?;
+
+        let storage_proof = proof
+            .storage_proof
+            .iter()
+            .find(|entry| entry.key.as_b256() == key)
+            .ok_or(VerifyingProviderError::MissingStorageProof { address, key })?;
+
+        let mut encoded_value = Vec::new();
+        storage_proof.value.encode(&mut encoded_value);
+
+        let storage_key = Nibbles::unpack(keccak256(key));
+        verify_proof(
+            account.storage_root,
+            storage_key,
+            Some(encoded_value),
+            &storage_proof.proof,
+        )
+        .map_err(|_| VerifyingProviderError::InvalidStorageProof {
+            address,
+            key,
+            storage_root: account.storage_root,
+        })?;
+
+        let value =
+            self.inner.get_storage_at(address, U256::from_be_bytes(key.0)).block_id(block).await?;
+        if value != storage_proof.value {
+            return Err(VerifyingProviderError::InvalidStorageProof {
+                address,
+                key,
+                storage_root: account.storage_root,
+            });
+        }
+
+        Ok(value)
+    }
+
+    /// Get the code of `address` at `block`, verified against an `eth_getProof` for the same
+    /// account and block and against `block`'s `stateRoot` (see [`Self::get_state_root`]).
+    pub async fn get_verified_code_at(
+        &self,
+        address: Address,
+        block: BlockId,
+    ) -> Result<Bytes, VerifyingProviderError> {
+        let state_root = self.get_state_root(block).await?;
+        let proof = self.get_proof(address, Vec::new(), block).await?;
+        let account = Self::verify_account(&proof, state_root)?;
+
+        let code = self.inner.get_code_at(address).block_id(block).await?;
+        let code_hash = keccak256(&code);
+        if code_hash != account.code_hash {
+            return Err(VerifyingProviderError::InvalidCodeHash {
+                address,
+                code_hash: account.code_hash,
+            });
+        }
+
+        Ok(code)
+    }
+}
@@ -42,6 +42,8 @@ pub mod layers;
 
 mod chain;
 
+pub mod fees;
+
 mod heart;
 pub use heart::{
     PendingTransaction, PendingTransactionBuilder, PendingTransactionConfig,
@@ -0,0 +1,124 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// Safety margin added on top of the learned per-method average when
+/// [`IcpConfig::enable_cycle_learning`](crate::IcpConfig::enable_cycle_learning) is set, to
+/// absorb variance between calls to the same method.
+const LEARNED_COST_SAFETY_MARGIN_PERCENT: u128 = 20;
+
+#[derive(Debug, Default)]
+struct MethodCycleStats {
+    calls: u64,
+    total_cycles: u128,
+}
+
+impl MethodCycleStats {
+    fn record(&mut self, cycles: u128) {
+        self.calls += 1;
+        self.total_cycles += cycles;
+    }
+
+    fn average(&self) -> u128 {
+        if self.calls == 0 {
+            0
+        } else {
+            self.total_cycles / self.calls as u128
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CyclesAccountingInner {
+    total_spent: u128,
+    per_method: HashMap<String, MethodCycleStats>,
+}
+
+/// Tracks the cycles actually consumed (attached minus refunded) by each `evm_rpc` canister call
+/// made through an [`IcpTransport`](crate::IcpTransport), broken down per JSON-RPC method.
+///
+/// Cloning an [`IcpTransport`] shares its [`CyclesAccounting`], so cumulative spend is tracked
+/// across every clone of a given transport instance.
+#[derive(Clone, Debug, Default)]
+pub struct CyclesAccounting {
+    inner: Rc<RefCell<CyclesAccountingInner>>,
+}
+
+impl CyclesAccounting {
+    /// Record that `cycles_spent` were actually consumed (i.e. not refunded) by a call to
+    /// `method`.
+    pub(crate) fn record(&self, method: &str, cycles_spent: u128) {
+        let mut inner = self.inner.borrow_mut();
+        inner.total_spent += cycles_spent;
+        inner.per_method.entry(method.to_owned()).or_default().record(cycles_spent);
+    }
+
+    /// Total cycles spent across every call recorded so far.
+    pub fn total_spent(&self) -> u128 {
+        self.inner.borrow().total_spent
+    }
+
+    /// Number of calls recorded for `method`.
+    pub fn calls_for(&self, method: &str) -> u64 {
+        self.inner.borrow().per_method.get(method).map_or(0, |stats| stats.calls)
+    }
+
+    /// Average cycles spent per call to `method`, or `None` if no call has been recorded yet.
+    pub fn average_cycles_for(&self, method: &str) -> Option<u128> {
+        let inner = self.inner.borrow();
+        let stats = inner.per_method.get(method)?;
+        (stats.calls > 0).then(|| stats.average())
+    }
+
+    /// The learned cost estimate for `method`: the average recorded cost plus a
+    /// [`LEARNED_COST_SAFETY_MARGIN_PERCENT`] safety margin, or `None` if no call has been
+    /// recorded yet.
+    pub(crate) fn estimate_call_cycles(&self, method: &str) -> Option<u128> {
+        self.average_cycles_for(method)
+            .map(|average| average + average * LEARNED_COST_SAFETY_MARGIN_PERCENT / 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unrecorded_method_has_no_stats() {
+        let accounting = CyclesAccounting::default();
+        assert_eq!(accounting.calls_for("eth_getBalance"), 0);
+        assert_eq!(accounting.average_cycles_for("eth_getBalance"), None);
+        assert_eq!(accounting.estimate_call_cycles("eth_getBalance"), None);
+        assert_eq!(accounting.total_spent(), 0);
+    }
+
+    #[test]
+    fn record_tracks_totals_and_averages_per_method() {
+        let accounting = CyclesAccounting::default();
+        accounting.record("eth_getBalance", 100);
+        accounting.record("eth_getBalance", 200);
+        accounting.record("eth_call", 50);
+
+        assert_eq!(accounting.calls_for("eth_getBalance"), 2);
+        assert_eq!(accounting.average_cycles_for("eth_getBalance"), Some(150));
+        assert_eq!(accounting.calls_for("eth_call"), 1);
+        assert_eq!(accounting.average_cycles_for("eth_call"), Some(50));
+        assert_eq!(accounting.total_spent(), 350);
+    }
+
+    #[test]
+    fn clones_share_the_same_underlying_accounting() {
+        let accounting = CyclesAccounting::default();
+        let clone = accounting.clone();
+        clone.record("eth_getBalance", 100);
+
+        assert_eq!(accounting.calls_for("eth_getBalance"), 1);
+        assert_eq!(accounting.total_spent(), 100);
+    }
+
+    #[test]
+    fn estimate_call_cycles_adds_the_safety_margin() {
+        let accounting = CyclesAccounting::default();
+        accounting.record("eth_getBalance", 100);
+        // 100 + 20% margin = 120.
+        assert_eq!(accounting.estimate_call_cycles("eth_getBalance"), Some(120));
+    }
+}
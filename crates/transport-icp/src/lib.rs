@@ -13,14 +13,17 @@
     clippy::enum_variant_names,
     clippy::large_enum_variant
 )]
+mod cycles;
 mod evm_rpc;
 
 use alloy_json_rpc::{RequestPacket, ResponsePacket, SerializedRequest};
 use alloy_transport::{TransportError, TransportFut};
+use futures::future::join_all;
 use ic_cdk::api::call::CallResult;
-use std::task;
+use std::{collections::HashMap, task};
 use tower::Service;
 
+pub use cycles::CyclesAccounting;
 pub use evm_rpc::*;
 
 const DEFAULT_CALL_CYCLES: u128 = 60_000_000_000;
@@ -29,20 +32,88 @@ const MAX_RESPONSE_SIZE_SMALL: u64 = 1_000;
 const MAX_RESPONSE_SIZE_MEDIUM: u64 = 2_000;
 const MAX_RESPONSE_SIZE_UNKNOWN: u64 = 5_000;
 
+/// Default number of times the transport will double `max_response_size` and retry a request
+/// after the canister reports that the response did not fit.
+const DEFAULT_MAX_RESPONSE_SIZE_RETRIES: u8 = 2;
+
+/// Hard upper bound on [`IcpConfig::set_max_response_size_retries`], regardless of what the
+/// caller requests.
+const MAX_RESPONSE_SIZE_RETRIES_CAP: u8 = 5;
+
+/// The literal wording the management canister uses, inside an IC-level `SysFatal` reject, when
+/// an inter-canister HTTP outcall's response did not fit in the `max_response_bytes` that was
+/// attached to it. This is the only part of the condition that isn't already captured
+/// structurally by matching on [`RpcError::HttpOutcallError`]'s
+/// `HttpOutcallError::IcError { code: RejectionCode::SysFatal, .. }` shape, since `SysFatal` also
+/// covers unrelated fatal IC errors.
+const RESPONSE_TOO_LARGE_MESSAGE: &str = "body exceeds size limit";
+
+/// Strategy used to reconcile JSON-RPC results returned by the multiple [`RpcService`]s
+/// configured on an [`IcpTransport`] via [`IcpConfig::with_services`].
+///
+/// Single-provider transports ignore this setting entirely.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsensusStrategy {
+    /// Every configured provider must return a byte-identical result.
+    Equality,
+    /// At least `min` out of the `total` configured providers must return a byte-identical
+    /// result.
+    Threshold {
+        /// The number of providers that were queried.
+        total: u8,
+        /// The minimum number of byte-identical responses required to reach consensus.
+        min: u8,
+    },
+}
+
 /// Configuration details for an ICP transport.
 #[derive(Clone, Debug)]
 #[doc(hidden)]
 pub struct IcpConfig {
-    rpc_service: RpcService,
+    services: Vec<RpcService>,
+    consensus_strategy: ConsensusStrategy,
     call_cycles: Option<u128>,
     max_response_size: Option<u64>,
+    max_response_size_retries: u8,
+    learn_cycle_cost: bool,
 }
 
 impl IcpConfig {
     /// Create a new [`IcpConfig`] with the given [`RpcService`] and default values for call cycles
     /// and max response size.
-    pub const fn new(rpc_service: RpcService) -> Self {
-        Self { rpc_service, call_cycles: None, max_response_size: None }
+    pub fn new(rpc_service: RpcService) -> Self {
+        Self {
+            services: vec![rpc_service],
+            consensus_strategy: ConsensusStrategy::Equality,
+            call_cycles: None,
+            max_response_size: None,
+            max_response_size_retries: DEFAULT_MAX_RESPONSE_SIZE_RETRIES,
+            learn_cycle_cost: false,
+        }
+    }
+
+    /// Create a new [`IcpConfig`] that queries several [`RpcService`]s and only accepts a result
+    /// once they agree, per the given [`ConsensusStrategy`] (defaults to [`ConsensusStrategy::Equality`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `services` is empty.
+    pub fn with_services(services: Vec<RpcService>) -> Self {
+        assert!(!services.is_empty(), "IcpConfig::with_services requires at least one RpcService");
+        Self {
+            services,
+            consensus_strategy: ConsensusStrategy::Equality,
+            call_cycles: None,
+            max_response_size: None,
+            max_response_size_retries: DEFAULT_MAX_RESPONSE_SIZE_RETRIES,
+            learn_cycle_cost: false,
+        }
+    }
+
+    /// Set the [`ConsensusStrategy`] used to reconcile results from multiple providers.
+    pub const fn set_consensus_strategy(mut self, consensus_strategy: ConsensusStrategy) -> Self {
+        self.consensus_strategy = consensus_strategy;
+        self
     }
 
     /// Set the call cycles for this config.
@@ -56,37 +127,84 @@ impl IcpConfig {
         self.max_response_size = Some(max_response_size);
         self
     }
+
+    /// Set the number of times the transport may double `max_response_size` and retry a request
+    /// after the canister reports that the response was too large. Capped at
+    /// [`MAX_RESPONSE_SIZE_RETRIES_CAP`].
+    pub const fn set_max_response_size_retries(mut self, retries: u8) -> Self {
+        self.max_response_size_retries =
+            if retries > MAX_RESPONSE_SIZE_RETRIES_CAP { MAX_RESPONSE_SIZE_RETRIES_CAP } else { retries };
+        self
+    }
+
+    /// Enable learning per-method cycle costs from actual call results, replacing
+    /// `DEFAULT_CALL_CYCLES` with a per-method estimate (plus a safety margin) once at least one
+    /// call to that method has completed. Has no effect when [`Self::set_call_cycles`] is set,
+    /// since an explicit override always takes precedence.
+    pub const fn enable_cycle_learning(mut self) -> Self {
+        self.learn_cycle_cost = true;
+        self
+    }
 }
 
 /// An ICP transport.
 ///
 /// The user must provide an [`RpcService`] that specifies what
-/// chain and provider to use
+/// chain and provider to use. If more than one [`RpcService`] is configured (see
+/// [`IcpConfig::with_services`]), the transport queries all of them and only returns a result
+/// once they agree per the configured [`ConsensusStrategy`], so that no single provider is
+/// trusted blindly.
 #[derive(Clone, Debug)]
 pub struct IcpTransport {
-    rpc_service: RpcService,
+    services: Vec<RpcService>,
+    consensus_strategy: ConsensusStrategy,
     call_cycles: Option<u128>,
     max_response_size: Option<u64>,
+    max_response_size_retries: u8,
+    learn_cycle_cost: bool,
+    cycles_accounting: CyclesAccounting,
 }
 
 impl IcpTransport {
     /// Create a new [`IcpTransport`] using the given [`IcpConfig`] details.
     pub fn with_config(config: IcpConfig) -> Self {
         Self {
-            rpc_service: config.rpc_service,
+            services: config.services,
+            consensus_strategy: config.consensus_strategy,
             call_cycles: config.call_cycles,
             max_response_size: config.max_response_size,
+            max_response_size_retries: config.max_response_size_retries,
+            learn_cycle_cost: config.learn_cycle_cost,
+            cycles_accounting: CyclesAccounting::default(),
         }
     }
 
-    /// Set the [`RpcService`] for this transport.
+    /// Set the [`RpcService`] for this transport, switching it to single-provider mode.
     pub fn set_rpc_service(&mut self, rpc_service: RpcService) {
-        self.rpc_service = rpc_service;
+        self.services = vec![rpc_service];
+    }
+
+    /// Set the [`RpcService`]s this transport queries for consensus, per `strategy`.
+    pub fn set_services(&mut self, services: Vec<RpcService>, strategy: ConsensusStrategy) {
+        assert!(!services.is_empty(), "IcpTransport::set_services requires at least one RpcService");
+        self.services = services;
+        self.consensus_strategy = strategy;
+    }
+
+    /// Get a reference to the rpc service used for single-provider requests, i.e. the first of
+    /// the configured [`Self::services`].
+    pub fn rpc_service(&self) -> &RpcService {
+        &self.services[0]
     }
 
-    /// Get a reference to the rpc service.
-    pub const fn rpc_service(&self) -> &RpcService {
-        &self.rpc_service
+    /// Get the [`RpcService`]s this transport queries.
+    pub fn services(&self) -> &[RpcService] {
+        &self.services
+    }
+
+    /// Get the [`ConsensusStrategy`] used when more than one service is configured.
+    pub const fn consensus_strategy(&self) -> ConsensusStrategy {
+        self.consensus_strategy
     }
 
     /// Set the call cycles for this transport.
@@ -99,6 +217,23 @@ impl IcpTransport {
         self.call_cycles
     }
 
+    /// Enable or disable learning per-method cycle costs from actual call results. See
+    /// [`IcpConfig::enable_cycle_learning`].
+    pub fn set_learn_cycle_cost(&mut self, learn_cycle_cost: bool) {
+        self.learn_cycle_cost = learn_cycle_cost;
+    }
+
+    /// Whether this transport learns per-method cycle costs from actual call results.
+    pub const fn learn_cycle_cost(&self) -> bool {
+        self.learn_cycle_cost
+    }
+
+    /// Get the accumulated [`CyclesAccounting`] for this transport, shared across all of its
+    /// clones.
+    pub const fn cycles_accounting(&self) -> &CyclesAccounting {
+        &self.cycles_accounting
+    }
+
     /// Set the max response size for this transport.
     pub fn set_max_response_size(&mut self, max_response_size: u64) {
         self.max_response_size = Some(max_response_size);
@@ -109,6 +244,19 @@ impl IcpTransport {
         self.max_response_size
     }
 
+    /// Set the number of times the transport may double `max_response_size` and retry a request
+    /// after the canister reports that the response was too large. Capped at
+    /// [`MAX_RESPONSE_SIZE_RETRIES_CAP`].
+    pub fn set_max_response_size_retries(&mut self, retries: u8) {
+        self.max_response_size_retries =
+            if retries > MAX_RESPONSE_SIZE_RETRIES_CAP { MAX_RESPONSE_SIZE_RETRIES_CAP } else { retries };
+    }
+
+    /// Get the number of oversized-response retries this transport will attempt.
+    pub const fn max_response_size_retries(&self) -> u8 {
+        self.max_response_size_retries
+    }
+
     /// Check if the transport is local. Always `false` for now.
     pub const fn is_local(&self) -> bool {
         // Currently always returns false. We could add a check here to see
@@ -148,43 +296,200 @@ impl IcpTransport {
         }
     }
 
-    /// Make an EVM RPC request by calling the `request` method on the EVM RPC canister.
-    fn request(&self, request_packet: RequestPacket) -> TransportFut<'static> {
-        let rpc_service = self.rpc_service.clone();
-        let max_response_size =
-            self.max_response_size.unwrap_or(self.estimate_max_response_size(&request_packet));
-        let call_cycles = self.call_cycles.unwrap_or(DEFAULT_CALL_CYCLES);
+    /// Returns `true` if `rpc_error` indicates that the response did not fit within the
+    /// `max_response_size` that was attached to the call, as opposed to any other RPC failure.
+    ///
+    /// This matches the canister's structured error shape — an IC-level `SysFatal` reject
+    /// wrapped in [`RpcError::HttpOutcallError`] — rather than scanning the `Debug` formatting of
+    /// the whole (opaque, unrelated-variant-bearing) [`RpcError`]. `SysFatal` also covers other
+    /// fatal IC errors, so the reject message is still consulted, but only to disambiguate within
+    /// the one variant the overflow condition is actually reported through.
+    fn is_response_too_large(rpc_error: &RpcError) -> bool {
+        matches!(
+            rpc_error,
+            RpcError::HttpOutcallError(HttpOutcallError::IcError {
+                code: RejectionCode::SysFatal,
+                message,
+            }) if message.to_lowercase().contains(RESPONSE_TOO_LARGE_MESSAGE)
+        )
+    }
 
-        Box::pin(async move {
-            let serialized_request = request_packet.serialize().map_err(TransportError::ser_err)?;
+    /// The JSON-RPC method name `request_packet` is keyed by in [`CyclesAccounting`]: the single
+    /// method for a [`RequestPacket::Single`], or `"batch"` for a [`RequestPacket::Batch`], since
+    /// a batch's cost isn't attributable to any one method.
+    fn accounting_key(request_packet: &RequestPacket) -> &str {
+        match request_packet {
+            RequestPacket::Single(req) => req.meta().method.as_ref(),
+            RequestPacket::Batch(_) => "batch",
+        }
+    }
 
+    /// Call cycles to attach to this request: an explicit [`Self::call_cycles`] override always
+    /// wins, otherwise a learned per-method estimate is used once [`Self::learn_cycle_cost`] is
+    /// enabled and at least one prior call to `method` has been recorded, falling back to
+    /// `DEFAULT_CALL_CYCLES`.
+    fn call_cycles_for(&self, method: &str) -> u128 {
+        self.call_cycles.unwrap_or_else(|| {
+            self.learn_cycle_cost
+                .then(|| self.cycles_accounting.estimate_call_cycles(method))
+                .flatten()
+                .unwrap_or(DEFAULT_CALL_CYCLES)
+        })
+    }
+
+    /// Call the EVM RPC canister's `request` method for a single [`RpcService`], retrying with a
+    /// doubled `max_response_size` as long as the canister reports that the response did not fit,
+    /// up to `max_retries` times. The cycles actually consumed (attached minus refunded) are
+    /// recorded in `cycles_accounting` under `method`.
+    async fn call_service(
+        rpc_service: RpcService,
+        serialized_request: &SerializedRequest,
+        mut max_response_size: u64,
+        call_cycles: u128,
+        max_retries: u8,
+        method: &str,
+        cycles_accounting: &CyclesAccounting,
+    ) -> Result<String, TransportError> {
+        let mut attempt = 0;
+        loop {
             let call_result: CallResult<(RequestResult,)> = evm_rpc
                 .request(
-                    rpc_service,
+                    rpc_service.clone(),
                     serialized_request.to_string(),
                     max_response_size,
                     call_cycles,
                 )
                 .await;
+            let refunded = ic_cdk::api::call::msg_cycles_refunded128();
+            cycles_accounting.record(method, call_cycles.saturating_sub(refunded));
 
             match call_result {
-                Ok((request_result,)) => match request_result {
-                    RequestResult::Ok(ok_result) => serde_json::from_str(&ok_result)
-                        .map_err(|err| TransportError::deser_err(err, &ok_result)),
-                    RequestResult::Err(rpc_error) => {
-                        Err(TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
-                            code: 6, // RPC error
-                            message: format!("{:?}", rpc_error),
-                            data: None,
-                        }))
+                Ok((RequestResult::Ok(ok_result),)) => return Ok(ok_result),
+                Ok((RequestResult::Err(rpc_error),)) => {
+                    if attempt < max_retries && Self::is_response_too_large(&rpc_error) {
+                        attempt += 1;
+                        max_response_size *= 2;
+                        continue;
                     }
-                },
-                Err(err) => Err(TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
-                    code: err.0 as i64,
-                    message: err.1,
-                    data: None,
-                })),
+
+                    return Err(TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
+                        code: 6, // RPC error
+                        message: format!("{:?}", rpc_error),
+                        data: None,
+                    }));
+                }
+                Err(err) => {
+                    return Err(TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
+                        code: err.0 as i64,
+                        message: err.1,
+                        data: None,
+                    }));
+                }
             }
+        }
+    }
+
+    /// Reconcile the per-provider results returned by each configured service per `strategy`,
+    /// returning the agreed-upon result or an inconsistency error listing both the divergent
+    /// responses and any per-provider failures.
+    ///
+    /// A provider erroring (e.g. a timeout) does not short-circuit the whole call: only the
+    /// `Ok` results are tallied, so e.g. a `Threshold { min: 2, .. }` can still be met by two
+    /// agreeing providers even if a third returned an error. A result is only accepted if it is
+    /// the *unique* group of byte-identical responses meeting `strategy`'s required count; if no
+    /// group meets it, or more than one does (providers are split between two or more
+    /// disagreeing answers that each clear the bar), this errors rather than arbitrarily picking
+    /// one.
+    fn reconcile(
+        results: Vec<Result<String, TransportError>>,
+        strategy: ConsensusStrategy,
+    ) -> Result<String, TransportError> {
+        let total = results.len();
+        let (oks, errs): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+        let oks: Vec<String> = oks.into_iter().map(Result::unwrap).collect();
+        let errs: Vec<TransportError> = errs.into_iter().map(Result::unwrap_err).collect();
+
+        let mut tally: HashMap<&str, u8> = HashMap::new();
+        for result in &oks {
+            *tally.entry(result.as_str()).or_default() += 1;
+        }
+
+        let required = match strategy {
+            ConsensusStrategy::Equality => total as u8,
+            ConsensusStrategy::Threshold { min, .. } => min,
+        };
+
+        let mut qualifying = tally.iter().filter(|(_, &count)| count >= required);
+        let winner = qualifying.next();
+        let runner_up = qualifying.next();
+
+        if let (Some((agreed, _)), None) = (winner, runner_up) {
+            return Ok((*agreed).to_owned());
+        }
+
+        Err(TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
+            code: 6, // RPC error
+            message: format!(
+                "providers did not reach consensus (required {required} of {total}): \
+                 {} successful result(s) {oks:?}, {} provider error(s) {errs:?}",
+                oks.len(),
+                errs.len(),
+            ),
+            data: None,
+        }))
+    }
+
+    /// Make an EVM RPC request by calling the `request` method on the EVM RPC canister.
+    ///
+    /// If the canister reports that the response exceeded `max_response_size`, the ceiling is
+    /// doubled and the same [`SerializedRequest`] is re-issued, up to
+    /// [`IcpTransport::max_response_size_retries`] times. Any other error is propagated
+    /// immediately.
+    ///
+    /// If more than one [`RpcService`] is configured, every service is queried and the result is
+    /// only returned once they agree per [`IcpTransport::consensus_strategy`]; otherwise an
+    /// inconsistency error is returned.
+    fn request(&self, request_packet: RequestPacket) -> TransportFut<'static> {
+        let services = self.services.clone();
+        let consensus_strategy = self.consensus_strategy;
+        let max_response_size =
+            self.max_response_size.unwrap_or(self.estimate_max_response_size(&request_packet));
+        let method = Self::accounting_key(&request_packet).to_owned();
+        let call_cycles = self.call_cycles_for(&method);
+        let max_retries = self.max_response_size_retries;
+        let cycles_accounting = self.cycles_accounting.clone();
+
+        Box::pin(async move {
+            let serialized_request = request_packet.serialize().map_err(TransportError::ser_err)?;
+
+            let ok_result = if let [rpc_service] = services.as_slice() {
+                Self::call_service(
+                    rpc_service.clone(),
+                    &serialized_request,
+                    max_response_size,
+                    call_cycles,
+                    max_retries,
+                    &method,
+                    &cycles_accounting,
+                )
+                .await?
+            } else {
+                let calls = services.into_iter().map(|rpc_service| {
+                    Self::call_service(
+                        rpc_service,
+                        &serialized_request,
+                        max_response_size,
+                        call_cycles,
+                        max_retries,
+                        &method,
+                        &cycles_accounting,
+                    )
+                });
+                let results = join_all(calls).await;
+                Self::reconcile(results, consensus_strategy)?
+            };
+
+            serde_json::from_str(&ok_result).map_err(|err| TransportError::deser_err(err, &ok_result))
         })
     }
 }
@@ -222,3 +527,87 @@ impl Service<RequestPacket> for &IcpTransport {
         self.request(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok_results(values: &[&str]) -> Vec<Result<String, TransportError>> {
+        values.iter().map(|value| Ok(value.to_string())).collect()
+    }
+
+    fn transport_err() -> TransportError {
+        TransportError::ErrorResp(alloy_json_rpc::ErrorPayload {
+            code: -1,
+            message: "simulated provider failure".to_owned(),
+            data: None,
+        })
+    }
+
+    #[test]
+    fn reconcile_equality_agrees() {
+        let agreed =
+            IcpTransport::reconcile(ok_results(&["a", "a", "a"]), ConsensusStrategy::Equality)
+                .unwrap();
+        assert_eq!(agreed, "a");
+    }
+
+    #[test]
+    fn reconcile_equality_disagrees() {
+        let err = IcpTransport::reconcile(ok_results(&["a", "a", "b"]), ConsensusStrategy::Equality)
+            .unwrap_err();
+        assert!(format!("{err}").contains("consensus"));
+    }
+
+    #[test]
+    fn reconcile_threshold_meets_min() {
+        let agreed = IcpTransport::reconcile(
+            ok_results(&["a", "a", "b"]),
+            ConsensusStrategy::Threshold { total: 3, min: 2 },
+        )
+        .unwrap();
+        assert_eq!(agreed, "a");
+    }
+
+    #[test]
+    fn reconcile_threshold_no_group_meets_min() {
+        let err = IcpTransport::reconcile(
+            ok_results(&["a", "b", "c"]),
+            ConsensusStrategy::Threshold { total: 3, min: 2 },
+        )
+        .unwrap_err();
+        assert!(format!("{err}").contains("consensus"));
+    }
+
+    #[test]
+    fn reconcile_threshold_two_groups_meet_min_is_ambiguous() {
+        // 3-of-5 return "a", 2-of-5 return "b": both clear `min: 2`, so this must error rather
+        // than silently picking whichever the tally happens to iterate to first.
+        let err = IcpTransport::reconcile(
+            ok_results(&["a", "a", "a", "b", "b"]),
+            ConsensusStrategy::Threshold { total: 5, min: 2 },
+        )
+        .unwrap_err();
+        assert!(format!("{err}").contains("consensus"));
+    }
+
+    #[test]
+    fn reconcile_threshold_ignores_a_failed_provider_when_the_rest_agree() {
+        // One provider errored (e.g. timed out) but the other two still agree and clear
+        // `min: 2`: the error must not short-circuit a consensus the surviving providers reached.
+        let results = vec![Ok("a".to_owned()), Ok("a".to_owned()), Err(transport_err())];
+        let agreed =
+            IcpTransport::reconcile(results, ConsensusStrategy::Threshold { total: 3, min: 2 })
+                .unwrap();
+        assert_eq!(agreed, "a");
+    }
+
+    #[test]
+    fn reconcile_equality_fails_if_any_provider_errors() {
+        // Equality requires every configured provider to agree, so one failing (even if the
+        // rest agree) must still surface as an inconsistency, not succeed on a partial quorum.
+        let results = vec![Ok("a".to_owned()), Ok("a".to_owned()), Err(transport_err())];
+        let err = IcpTransport::reconcile(results, ConsensusStrategy::Equality).unwrap_err();
+        assert!(format!("{err}").contains("consensus"));
+    }
+}